@@ -17,55 +17,124 @@
 use arrayvec::ArrayVec;
 use bigint::U512;
 use parking_lot::{Mutex, MutexGuard};
+use rand::Rng;
 use std::mem;
 use std::slice::Iter as SliceIter;
 use std::time::{Duration, Instant};
 use std::vec::IntoIter as VecIntoIter;
 
-/// Maximum number of nodes in a bucket.
+/// Default maximum number of nodes in a bucket, a.k.a. `K` in Kademlia parlance.
 pub const MAX_NODES_PER_BUCKET: usize = 20;
 
+/// Default time-to-live of an entry before it's considered expired and pruned.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// Default interval after which a still-valid entry should be republished by the caller.
+pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(22 * 60 * 60);
+
+/// Default interval after which a bucket that hasn't been touched should be refreshed.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Example `KBucketsPeerId` implementation; nothing in this crate constructs one, so both the
+// type and its inherent method are otherwise flagged as dead code once tests activate
+// `cfg_attr(test, deny(warnings))`.
+#[allow(dead_code)]
 #[derive(Debug, Clone, Eq ,PartialEq)]
 struct PeerId {}
 
 impl PeerId {
+  #[allow(dead_code)]
   fn digest(&self) -> &[u8] {
     unimplemented!();
   }
 }
 
 /// Table of k-buckets with interior mutability.
+///
+/// `K` is the maximum number of nodes kept in each bucket, often called the
+/// replication factor. It defaults to [`MAX_NODES_PER_BUCKET`], but callers
+/// that need a different redundancy/memory trade-off can pick their own.
 #[derive(Debug)]
-pub struct KBucketsTable<Id, Val> {
+pub struct KBucketsTable<Id, Val, const K: usize = MAX_NODES_PER_BUCKET> {
   my_id: Id,
-  tables: Vec<Mutex<KBucket<Id, Val>>>,
+  tables: Vec<Mutex<KBucket<Id, Val, K>>>,
   ping_timeout: Duration,
+  default_ttl: Duration,
+  republish_interval: Duration,
+  refresh_interval: Duration,
 }
 
 #[derive(Debug, Clone)]
-struct KBucket<Id, Val> {
-  nodes: ArrayVec<[Node<Id, Val>; MAX_NODES_PER_BUCKET]>,
+struct KBucket<Id, Val, const K: usize> {
+  nodes: ArrayVec<Node<Id, Val>, K>,
   pending_node: Option<(Node<Id, Val>, Instant)>,
   last_update: Instant,
 }
 
-impl<Id, Val> KBucket<Id, Val> {
+impl<Id, Val, const K: usize> KBucket<Id, Val, K> {
   fn flush(&mut self, timeout: Duration) {
     if let Some((pending_node, instant)) = self.pending_node.take() {
       if instant.elapsed() >= timeout {
-        let _ = self.nodes.remove(0);
+        let mut pending_node = pending_node;
+        pending_node.status = NodeStatus::Connected;
+        let victim = self.least_reliable_index();
+        let _ = self.nodes.remove(victim);
         self.nodes.push(pending_node);
       } else {
         self.pending_node = Some((pending_node, instant));
       }
     }
+
+    let now = Instant::now();
+    self.nodes.retain(|node| node.expires_at > now);
+  }
+
+  // Returns the index of the node that is the best eviction candidate: an `Unreachable`
+  // node if there is one, otherwise the node with the highest failure count. Falls back to
+  // the head of the bucket (the oldest entry) when every node is equally reliable.
+  fn least_reliable_index(&self) -> usize {
+    self
+      .nodes
+      .iter()
+      .enumerate()
+      .max_by_key(|(pos, node)| {
+        (
+          node.status == NodeStatus::Unreachable,
+          node.failures,
+          std::cmp::Reverse(*pos),
+        )
+      })
+      .map(|(pos, _)| pos)
+      .unwrap_or(0)
   }
 }
 
+/// Liveness state of a [`Node`] inside a bucket.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NodeStatus {
+  /// The node has recently responded and is presumed reachable.
+  Connected,
+  /// The node has been reported as failing, but fewer times than it takes to become
+  /// `Unreachable`.
+  Disconnected,
+  /// The node is queued for a full bucket while the bucket's head is being pinged.
+  Pending,
+  /// The node has failed enough consecutive pings that it's considered unreachable.
+  Unreachable,
+}
+
+// Number of consecutive failures after which a node flips from `Disconnected` to
+// `Unreachable` and becomes the prime eviction candidate.
+const UNREACHABLE_AFTER_FAILURES: u32 = 3;
+
 #[derive(Debug, Clone)]
 struct Node<Id, Val> {
   id: Id,
   value: Val,
+  status: NodeStatus,
+  failures: u32,
+  expires_at: Instant,
+  republish_at: Instant,
 }
 
 /// Trait that must be implemented on types that can be used as an identifier in
@@ -85,6 +154,12 @@ pub trait KBucketsPeerId: Eq + Clone {
 
   /// Returns the number of leading zeroes of the distance between peer IDs.
   fn leading_zeros(distance: Self::Distance) -> u32;
+
+  /// Generates a random distance whose leading-zero count places it in bucket `bucket_num`
+  /// (i.e. exactly `num_bits() - 1 - bucket_num` leading zero bits, with the remaining lower
+  /// bits randomized). XORing the result with a peer ID yields a random key that falls
+  /// inside that bucket, which is what periodic bucket refresh looks up.
+  fn random_distance(bucket_num: usize) -> Self::Distance;
 }
 
 impl KBucketsPeerId for PeerId {
@@ -108,14 +183,36 @@ impl KBucketsPeerId for PeerId {
   fn leading_zeros(distance: Self::Distance) -> u32 {
     distance.leading_zeros()
   }
+
+  fn random_distance(bucket_num: usize) -> Self::Distance {
+    // The bit at `bucket_num` (counted from the least-significant bit) must be set so that
+    // the result has exactly `num_bits() - 1 - bucket_num` leading zeroes; every bit below it
+    // is randomized and every bit above it stays zero.
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill(&mut bytes[..]);
+    let random = U512::from(&bytes[..]);
+    let bit = U512::from(1u64) << bucket_num;
+    (random & (bit - U512::from(1u64))) | bit
+  }
 }
 
-impl<Id, Val> KBucketsTable<Id, Val>
+impl<Id, Val, const K: usize> KBucketsTable<Id, Val, K>
 where
   Id: KBucketsPeerId,
 {
   /// Builds a new routing table.
+  ///
+  /// Entries default to [`DEFAULT_TTL`] and [`DEFAULT_REPUBLISH_INTERVAL`]; use
+  /// [`with_ttl`](Self::with_ttl) and [`with_republish_interval`](Self::with_republish_interval)
+  /// to override them.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `K` is 0: a bucket needs room for at least one node, and [`update`](Self::update)
+  /// would otherwise index into an empty bucket on its very first call.
   pub fn new(my_id: Id, ping_timeout: Duration) -> Self {
+    assert!(K > 0, "KBucketsTable requires a bucket capacity K of at least 1");
+
     KBucketsTable {
       my_id: my_id,
       tables: (0..Id::num_bits())
@@ -126,9 +223,30 @@ where
         }).map(Mutex::new)
         .collect(),
       ping_timeout: ping_timeout,
+      default_ttl: DEFAULT_TTL,
+      republish_interval: DEFAULT_REPUBLISH_INTERVAL,
+      refresh_interval: DEFAULT_REFRESH_INTERVAL,
     }
   }
 
+  /// Overrides the default time-to-live applied to entries added through [`update`](Self::update).
+  pub fn with_ttl(mut self, ttl: Duration) -> Self {
+    self.default_ttl = ttl;
+    self
+  }
+
+  /// Overrides the republish interval used by [`entries_needing_republish`](Self::entries_needing_republish).
+  pub fn with_republish_interval(mut self, interval: Duration) -> Self {
+    self.republish_interval = interval;
+    self
+  }
+
+  /// Overrides the refresh interval used by [`buckets_needing_refresh`](Self::buckets_needing_refresh).
+  pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+    self.refresh_interval = interval;
+    self
+  }
+
   // Returns the id of the bucket that should contain the peer with the given ID.
   //
   // Returns `None` if out of range, which happens if `id` is the same as the local peer id.
@@ -143,7 +261,7 @@ where
   /// Ordered by proximity to the local node. Closest bucket (with max. one node in it) comes
   /// first.
   #[inline]
-  pub fn buckets(&self) -> BucketsIter<'_, Id, Val> {
+  pub fn buckets(&self) -> BucketsIter<'_, Id, Val, K> {
     BucketsIter(self.tables.iter(), self.ping_timeout)
   }
 
@@ -153,25 +271,24 @@ where
     &self.my_id
   }
 
-  /// Finds the `num` nodes closest to `id`, ordered by distance.
-  pub fn find_closest(&self, id: &Id) -> VecIntoIter<Id>
+  /// Finds the nodes closest to `id`, in ascending order of true XOR distance to `id`.
+  ///
+  /// Bucket assignment is relative to the local peer id, not to `id`, so a bucket's
+  /// index-proximity to `id`'s own bucket doesn't bound how close its members actually are to
+  /// `id`: a bucket several steps away by index can still hold a node closer than one right
+  /// next door. There's no way to know which buckets matter without examining all of them, so
+  /// the first call into the returned iterator locks, flushes, and collects every bucket and
+  /// sorts the result by true distance; that order is then buffered and drained as the
+  /// iterator is consumed.
+  pub fn find_closest(&self, id: &Id) -> ClosestIter<'_, Id, Val, K>
   where
     Id: Clone,
   {
-    // TODO: optimize
-    let mut out = Vec::new();
-    for table in self.tables.iter() {
-      let mut table = table.lock();
-      table.flush(self.ping_timeout);
-      if table.last_update.elapsed() > self.ping_timeout {
-        continue; // ignore bucket with expired nodes
-      }
-      for node in table.nodes.iter() {
-        out.push(node.id.clone());
-      }
+    ClosestIter {
+      table: self,
+      target: id.clone(),
+      sorted: None,
     }
-    out.sort_by(|a, b| b.distance_with(id).cmp(&a.distance_with(id)));
-    out.into_iter()
   }
 
   /// Same as `find_closest`, but includes the local peer as well.
@@ -179,7 +296,6 @@ where
   where
     Id: Clone,
   {
-    // TODO: optimize
     let mut intermediate: Vec<_> = self.find_closest(&id).collect();
     if let Some(pos) = intermediate
       .iter()
@@ -194,9 +310,53 @@ where
     intermediate.into_iter()
   }
 
+  /// Finds the `num` nodes closest to `id`, preferring reachable nodes over questionable ones.
+  ///
+  /// Candidates are first split into two reliability tiers -- nodes that are currently
+  /// `Connected` and haven't recorded any failure, and everything else -- and are only
+  /// ordered by XOR distance to `id` within each tier. This means the nodes returned first
+  /// are the ones most likely to actually answer a query, even if a farther-but-failing node
+  /// would otherwise come first by distance alone.
+  pub fn find_preferred_closest(&self, id: &Id, num: usize) -> VecIntoIter<Id>
+  where
+    Id: Clone,
+  {
+    let mut preferred = Vec::new();
+    let mut rest = Vec::new();
+    for table in self.tables.iter() {
+      let mut table = table.lock();
+      table.flush(self.ping_timeout);
+      if table.last_update.elapsed() > self.ping_timeout {
+        continue; // ignore bucket with expired nodes
+      }
+      for node in table.nodes.iter() {
+        if node.status == NodeStatus::Connected && node.failures == 0 {
+          preferred.push(node.id.clone());
+        } else {
+          rest.push(node.id.clone());
+        }
+      }
+    }
+    preferred.sort_by(|a, b| a.distance_with(id).cmp(&b.distance_with(id)));
+    rest.sort_by(|a, b| a.distance_with(id).cmp(&b.distance_with(id)));
+    preferred.extend(rest);
+    preferred.truncate(num);
+    preferred.into_iter()
+  }
+
   /// Marks the node as "most recent" in its bucket and modifies the value associated to it.
   /// This function should be called whenever we receive a communication from a node.
+  ///
+  /// The entry is stored with the table's default TTL (see [`DEFAULT_TTL`] and
+  /// [`with_ttl`](Self::with_ttl)). Use [`update_with_ttl`](Self::update_with_ttl) to pick a
+  /// TTL for this entry specifically.
   pub fn update(&self, id: Id, value: Val) -> UpdateOutcome<Id, Val> {
+    self.update_with_ttl(id, value, self.default_ttl)
+  }
+
+  /// Same as [`update`](Self::update), but stores the entry with the given time-to-live
+  /// instead of the table's default.
+  pub fn update_with_ttl(&self, id: Id, value: Val, ttl: Duration) -> UpdateOutcome<Id, Val> {
     let table = match self.bucket_num(&id) {
       Some(n) => &self.tables[n],
       None => return UpdateOutcome::FailSelfUpdate,
@@ -205,45 +365,230 @@ where
     let mut table = table.lock();
     table.flush(self.ping_timeout);
 
+    let now = Instant::now();
+    let expires_at = now + ttl;
+    let republish_at = now + self.republish_interval;
+
     if let Some(pos) = table.nodes.iter().position(|n| n.id == id) {
       // Node is already in the bucket.
       let mut existing = table.nodes.remove(pos);
       let old_val = mem::replace(&mut existing.value, value);
+      existing.status = NodeStatus::Connected;
+      existing.failures = 0;
+      existing.expires_at = expires_at;
+      existing.republish_at = republish_at;
       if pos == 0 {
         // If it's the first node of the bucket that we update, then we drop the node that
         // was waiting for a ping.
-        table.nodes.truncate(MAX_NODES_PER_BUCKET - 1);
+        table.nodes.truncate(K - 1);
         table.pending_node = None;
       }
       table.nodes.push(existing);
-      table.last_update = Instant::now();
+      table.last_update = now;
       UpdateOutcome::Refreshed(old_val)
-    } else if table.nodes.len() < MAX_NODES_PER_BUCKET {
+    } else if table.nodes.len() < K {
       // Node not yet in the bucket, but there's plenty of space.
       table.nodes.push(Node {
         id: id,
         value: value,
+        status: NodeStatus::Connected,
+        failures: 0,
+        expires_at: expires_at,
+        republish_at: republish_at,
       });
-      table.last_update = Instant::now();
+      table.last_update = now;
       UpdateOutcome::Added
-    } else {
-      // Not enough space to put the node, but we can add it to the end as "pending". We
-      // then need to tell the caller that we want it to ping the node at the top of the
-      // list.
+    } else if table.nodes[0].status == NodeStatus::Connected {
+      // The bucket is full, but the node at the head still looks reachable, so we only
+      // evict it if it actually fails to answer a ping. Queue the newcomer as pending and
+      // ask the caller to ping the head.
       if table.pending_node.is_none() {
         table.pending_node = Some((
           Node {
             id: id,
             value: value,
+            status: NodeStatus::Pending,
+            failures: 0,
+            expires_at: expires_at,
+            republish_at: republish_at,
           },
-          Instant::now(),
+          now,
         ));
         UpdateOutcome::NeedPing(table.nodes[0].id.clone())
       } else {
         UpdateOutcome::Discarded
       }
+    } else if let Some((mut pending_node, _)) = table.pending_node.take() {
+      // The head is already known to be unreliable, and a ping was already underway for a
+      // different candidate: honor that promise by promoting it into the vacated slot instead
+      // of discarding it in favor of whoever happens to trigger this particular call. `id`
+      // isn't added; the caller is free to call `update` for it again later.
+      pending_node.status = NodeStatus::Connected;
+      let victim = table.least_reliable_index();
+      let _ = table.nodes.remove(victim);
+      table.nodes.push(pending_node);
+      table.last_update = now;
+      UpdateOutcome::Discarded
+    } else {
+      // The head is already known to be unreliable and nothing is pending: no point pinging
+      // it, just evict the least-reliable node in the bucket right away.
+      let victim = table.least_reliable_index();
+      let _ = table.nodes.remove(victim);
+      table.nodes.push(Node {
+        id: id,
+        value: value,
+        status: NodeStatus::Connected,
+        failures: 0,
+        expires_at: expires_at,
+        republish_at: republish_at,
+      });
+      table.last_update = now;
+      UpdateOutcome::Added
+    }
+  }
+
+  /// Reports that a node failed to respond to a ping or a request.
+  ///
+  /// This bumps its failure counter and moves it to `Disconnected`, or to `Unreachable`
+  /// once [`UNREACHABLE_AFTER_FAILURES`] consecutive failures have been reported, making it
+  /// the preferred eviction candidate the next time its bucket needs to make room.
+  pub fn report_unreachable(&self, id: &Id) {
+    let table = match self.bucket_num(id) {
+      Some(n) => &self.tables[n],
+      None => return,
+    };
+
+    let mut table = table.lock();
+    if let Some(node) = table.nodes.iter_mut().find(|n| &n.id == id) {
+      node.failures = node.failures.saturating_add(1);
+      node.status = if node.failures >= UNREACHABLE_AFTER_FAILURES {
+        NodeStatus::Unreachable
+      } else {
+        NodeStatus::Disconnected
+      };
     }
   }
+
+  /// Returns the ids of the entries whose TTL has already elapsed.
+  ///
+  /// Expired entries are pruned automatically the next time their bucket is flushed (e.g. via
+  /// [`buckets`](Self::buckets), [`find_closest`](Self::find_closest), or
+  /// [`update`](Self::update)); this is mainly useful for inspection or logging beforehand.
+  pub fn expired_entries(&self) -> Vec<Id>
+  where
+    Id: Clone,
+  {
+    let now = Instant::now();
+    let mut out = Vec::new();
+    for table in self.tables.iter() {
+      let table = table.lock();
+      for node in table.nodes.iter() {
+        if node.expires_at <= now {
+          out.push(node.id.clone());
+        }
+      }
+    }
+    out
+  }
+
+  /// Returns the `(id, value)` pairs whose republish interval has elapsed, so the caller can
+  /// re-announce them before they expire.
+  ///
+  /// This doesn't reset `republish_at` by itself, so an entry keeps being returned on every
+  /// call until either [`update`](Self::update)/[`update_with_ttl`](Self::update_with_ttl) is
+  /// called again for it, or the caller calls
+  /// [`mark_republished`](Self::mark_republished) once it has actually re-announced it.
+  pub fn entries_needing_republish(&self) -> Vec<(Id, Val)>
+  where
+    Id: Clone,
+    Val: Clone,
+  {
+    let now = Instant::now();
+    let mut out = Vec::new();
+    for table in self.tables.iter() {
+      let table = table.lock();
+      for node in table.nodes.iter() {
+        if node.republish_at <= now {
+          out.push((node.id.clone(), node.value.clone()));
+        }
+      }
+    }
+    out
+  }
+
+  /// Resets the republish timer of an entry, to be called once the caller has actually
+  /// re-announced it after [`entries_needing_republish`](Self::entries_needing_republish)
+  /// reported it. Does nothing if the entry isn't present in the table.
+  pub fn mark_republished(&self, id: &Id) {
+    let table = match self.bucket_num(id) {
+      Some(n) => &self.tables[n],
+      None => return,
+    };
+
+    let mut table = table.lock();
+    if let Some(node) = table.nodes.iter_mut().find(|n| &n.id == id) {
+      node.republish_at = Instant::now() + self.republish_interval;
+    }
+  }
+
+  /// Generates a random distance that falls inside bucket `bucket_num`.
+  ///
+  /// XOR the result with [`my_id`](Self::my_id) to get a key to look up, which is what
+  /// periodic bucket refresh uses to keep a bucket's contents fresh.
+  pub fn random_refresh_target(&self, bucket_num: usize) -> Id::Distance {
+    Id::random_distance(bucket_num)
+  }
+
+  /// Returns the indices of the buckets that haven't seen an update in longer than the
+  /// refresh interval (see [`with_refresh_interval`](Self::with_refresh_interval)) and
+  /// should have a random key inside their range looked up via
+  /// [`random_refresh_target`](Self::random_refresh_target).
+  pub fn buckets_needing_refresh(&self) -> VecIntoIter<usize> {
+    let mut out = Vec::new();
+    for (n, table) in self.tables.iter().enumerate() {
+      let table = table.lock();
+      if table.last_update.elapsed() >= self.refresh_interval {
+        out.push(n);
+      }
+    }
+    out.into_iter()
+  }
+}
+
+/// Lazy iterator returned by [`KBucketsTable::find_closest`].
+///
+/// The first call to `next` locks, flushes, and collects every bucket and sorts the result by
+/// true distance to the target; the sorted buffer is then drained for the rest of the
+/// iteration.
+#[allow(missing_debug_implementations)]
+pub struct ClosestIter<'a, Id, Val, const K: usize> {
+  table: &'a KBucketsTable<Id, Val, K>,
+  target: Id,
+  sorted: Option<VecIntoIter<Id>>,
+}
+
+impl<'a, Id, Val, const K: usize> Iterator for ClosestIter<'a, Id, Val, K>
+where
+  Id: KBucketsPeerId + Clone,
+{
+  type Item = Id;
+
+  fn next(&mut self) -> Option<Id> {
+    if self.sorted.is_none() {
+      let mut nodes = Vec::new();
+      for table in self.table.tables.iter() {
+        let mut table = table.lock();
+        table.flush(self.table.ping_timeout);
+        if table.last_update.elapsed() > self.table.ping_timeout {
+          continue; // ignore bucket with expired nodes
+        }
+        nodes.extend(table.nodes.iter().map(|node| node.id.clone()));
+      }
+      nodes.sort_by_key(|id| id.distance_with(&self.target));
+      self.sorted = Some(nodes.into_iter());
+    }
+    self.sorted.as_mut().unwrap().next()
+  }
 }
 
 /// Return value of the `update()` method.
@@ -265,13 +610,13 @@ pub enum UpdateOutcome<Id, Val> {
 
 /// Iterator giving access to a bucket.
 #[derive(Debug, Clone)]
-pub struct BucketsIter<'a, Id, Val>(
-  SliceIter<'a, Mutex<KBucket<Id, Val>>>,
+pub struct BucketsIter<'a, Id, Val, const K: usize>(
+  SliceIter<'a, Mutex<KBucket<Id, Val, K>>>,
   Duration,
 );
 
-impl<'a, Id, Val> Iterator for BucketsIter<'a, Id, Val> {
-  type Item = Bucket<'a, Id, Val>;
+impl<'a, Id, Val, const K: usize> Iterator for BucketsIter<'a, Id, Val, K> {
+  type Item = Bucket<'a, Id, Val, K>;
 
   #[inline]
   fn next(&mut self) -> Option<Self::Item> {
@@ -288,13 +633,13 @@ impl<'a, Id, Val> Iterator for BucketsIter<'a, Id, Val> {
   }
 }
 
-impl<'a, Id: 'a, Val: 'a> ExactSizeIterator for BucketsIter<'a, Id, Val> {}
+impl<'a, Id: 'a, Val: 'a, const K: usize> ExactSizeIterator for BucketsIter<'a, Id, Val, K> {}
 
 /// Access to a bucket.
 #[allow(missing_debug_implementations)]
-pub struct Bucket<'a, Id, Val>(MutexGuard<'a, KBucket<Id, Val>>);
+pub struct Bucket<'a, Id, Val, const K: usize>(MutexGuard<'a, KBucket<Id, Val, K>>);
 
-impl<'a, Id: 'a, Val: 'a> Bucket<'a, Id, Val> {
+impl<'a, Id: 'a, Val: 'a, const K: usize> Bucket<'a, Id, Val, K> {
   /// Returns the number of entries in that bucket.
   ///
   /// > **Note**: Keep in mind that this operation can be racy. If `update()` is called on the
@@ -319,3 +664,268 @@ impl<'a, Id: 'a, Val: 'a> Bucket<'a, Id, Val> {
     self.0.last_update.clone()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A minimal `KBucketsPeerId` impl used by the tests below, so they don't have to go through
+  // `PeerId`'s unimplemented `digest()`.
+  impl KBucketsPeerId for u64 {
+    type Distance = u64;
+
+    fn num_bits() -> usize {
+      64
+    }
+
+    fn distance_with(&self, other: &Self) -> Self::Distance {
+      self ^ other
+    }
+
+    fn leading_zeros(distance: Self::Distance) -> u32 {
+      distance.leading_zeros()
+    }
+
+    fn random_distance(bucket_num: usize) -> Self::Distance {
+      let mut bytes = [0u8; 8];
+      rand::thread_rng().fill(&mut bytes[..]);
+      let random = u64::from_le_bytes(bytes);
+      let bit = 1u64 << bucket_num;
+      (random & (bit - 1)) | bit
+    }
+  }
+
+  #[test]
+  fn custom_capacity_limits_bucket_size() {
+    let table: KBucketsTable<u64, (), 2> = KBucketsTable::new(0, Duration::from_secs(60));
+
+    // 4, 5 and 6 all share the same bucket (same highest set bit) relative to `my_id` 0.
+    assert_eq!(table.update(4, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(5, ()), UpdateOutcome::Added);
+
+    // With the default capacity of 20 this would also be `Added`; with `K` overridden to 2 the
+    // bucket is already full, so the newcomer is queued and the head is asked to be pinged.
+    match table.update(6, ()) {
+      UpdateOutcome::NeedPing(head) => assert_eq!(head, 4),
+      other => panic!("expected NeedPing once the K=2 capacity is reached, got {:?}", other),
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "KBucketsTable requires a bucket capacity K of at least 1")]
+  fn zero_capacity_panics_instead_of_indexing_an_empty_bucket() {
+    let _: KBucketsTable<u64, (), 0> = KBucketsTable::new(0, Duration::from_secs(60));
+  }
+
+  #[test]
+  fn full_bucket_eviction_prefers_unreachable_and_high_failure_nodes() {
+    let table: KBucketsTable<u64, (), 3> = KBucketsTable::new(0, Duration::from_secs(60));
+    assert_eq!(table.update(4, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(5, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(6, ()), UpdateOutcome::Added);
+
+    table.report_unreachable(&4); // head: one failure, stays Disconnected
+    for _ in 0..3 {
+      table.report_unreachable(&6); // three failures: flips to Unreachable
+    }
+
+    // The head (4) is no longer `Connected`, so the bucket evicts immediately instead of
+    // queuing a ping -- and it picks 6 (Unreachable) over the merely-Disconnected head.
+    assert_eq!(table.update(7, ()), UpdateOutcome::Added);
+
+    let remaining: Vec<u64> = table.find_closest(&4).collect();
+    assert!(remaining.contains(&4));
+    assert!(remaining.contains(&5));
+    assert!(remaining.contains(&7));
+    assert!(!remaining.contains(&6));
+  }
+
+  #[test]
+  fn eviction_tie_break_prefers_the_head_when_all_nodes_are_equally_reliable() {
+    let table: KBucketsTable<u64, (), 3> = KBucketsTable::new(0, Duration::from_millis(0));
+    // 8, 9, 10, 11 and 12 all share the same bucket (same highest set bit) relative to `my_id` 0.
+    assert_eq!(table.update(8, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(9, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(10, ()), UpdateOutcome::Added);
+
+    // None of 8, 9, 10 has failed a ping, so they're all equally reliable. With a zero
+    // `ping_timeout` the newcomer's `NeedPing` turns into an immediate eviction the next time
+    // `flush` runs, which must fall back to the head (8, the oldest entry) rather than the
+    // most-recently-added node (10).
+    match table.update(11, ()) {
+      UpdateOutcome::NeedPing(head) => assert_eq!(head, 8),
+      other => panic!("expected NeedPing, got {:?}", other),
+    }
+
+    // The next `update` flushes the pending ping timeout before doing anything else, evicting
+    // 8 (the head, as documented) and admitting 11 in its place -- so the bucket's new head is
+    // 9, which is who the following newcomer gets queued against.
+    match table.update(12, ()) {
+      UpdateOutcome::NeedPing(head) => assert_eq!(head, 9),
+      other => panic!("expected NeedPing, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn pending_node_is_promoted_when_the_head_turns_unreliable_before_the_ping_times_out() {
+    let table: KBucketsTable<u64, (), 3> = KBucketsTable::new(0, Duration::from_secs(60));
+    assert_eq!(table.update(16, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(17, ()), UpdateOutcome::Added);
+    assert_eq!(table.update(18, ()), UpdateOutcome::Added);
+
+    // The bucket is full and the head (16) still looks reachable, so 19 is queued as pending.
+    match table.update(19, ()) {
+      UpdateOutcome::NeedPing(head) => assert_eq!(head, 16),
+      other => panic!("expected NeedPing, got {:?}", other),
+    }
+
+    // The head fails enough pings to flip to `Unreachable` well before the ping timeout would
+    // have elapsed on its own.
+    for _ in 0..3 {
+      table.report_unreachable(&16);
+    }
+
+    // The bucket is still full and the head is now unreliable, so this evicts immediately --
+    // but it must promote the already-pending 19, not discard it in favor of the unrelated 20.
+    assert_eq!(table.update(20, ()), UpdateOutcome::Discarded);
+
+    let remaining: Vec<u64> = table.find_closest(&16).collect();
+    assert!(!remaining.contains(&16));
+    assert!(remaining.contains(&17));
+    assert!(remaining.contains(&18));
+    assert!(remaining.contains(&19));
+    assert!(!remaining.contains(&20));
+  }
+
+  #[test]
+  fn expired_entries_are_pruned_on_flush() {
+    let table: KBucketsTable<u64, (), 4> = KBucketsTable::new(0, Duration::from_secs(60));
+    assert_eq!(table.update_with_ttl(4, (), Duration::from_millis(1)), UpdateOutcome::Added);
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(table.expired_entries(), vec![4]);
+
+    // `find_closest` flushes every bucket it visits, pruning expired entries along the way.
+    assert_eq!(table.find_closest(&4).count(), 0);
+    assert!(table.expired_entries().is_empty());
+  }
+
+  #[test]
+  fn random_distance_falls_in_requested_bucket() {
+    for bucket_num in [0usize, 1, 37, 200, 511] {
+      let distance = PeerId::random_distance(bucket_num);
+      let expected_leading_zeros = (PeerId::num_bits() - 1 - bucket_num) as u32;
+      assert_eq!(PeerId::leading_zeros(distance), expected_leading_zeros);
+    }
+  }
+
+  #[test]
+  fn find_closest_yields_ascending_distance_order_within_a_bucket() {
+    let table: KBucketsTable<u64, (), 8> = KBucketsTable::new(0, Duration::from_secs(60));
+    // Inserted out of order; all four share a bucket relative to `my_id` 0.
+    for id in [6u64, 4, 7, 5] {
+      assert_eq!(table.update(id, ()), UpdateOutcome::Added);
+    }
+
+    let found: Vec<u64> = table.find_closest(&4).collect();
+    assert_eq!(found, vec![4, 5, 6, 7]); // ascending XOR distance to 4
+  }
+
+  #[test]
+  fn find_closest_is_globally_ascending_across_buckets() {
+    let table: KBucketsTable<u64, (), 8> = KBucketsTable::new(0, Duration::from_secs(60));
+    // Relative to `my_id` 0 and target 40, these land in five different buckets whose
+    // index-proximity to 40's own bucket doesn't match their true XOR distance to 40: e.g.
+    // 8 and 9 (two buckets away by index) are closer to 40 than 16, 17 and 24 (one bucket
+    // away), so a traversal that only concatenated bucket chunks by index would come back out
+    // of order.
+    for id in [1u64, 2, 3, 8, 9, 16, 17, 24, 32, 33, 48, 63] {
+      assert_eq!(table.update(id, ()), UpdateOutcome::Added);
+    }
+
+    let found: Vec<u64> = table.find_closest(&40).collect();
+    let distances: Vec<u64> = found.iter().map(|id| id ^ 40).collect();
+    let mut sorted = distances.clone();
+    sorted.sort();
+    assert_eq!(distances, sorted);
+  }
+
+  #[test]
+  fn find_closest_with_self_is_globally_ascending_across_buckets() {
+    let table: KBucketsTable<u64, (), 8> = KBucketsTable::new(0, Duration::from_secs(60));
+    // These land in four different buckets relative to `my_id` 0.
+    for id in [9u64, 4, 24, 3] {
+      assert_eq!(table.update(id, ()), UpdateOutcome::Added);
+    }
+
+    let found: Vec<u64> = table.find_closest_with_self(&8).collect();
+    assert!(found.contains(&0)); // the local peer id is included
+
+    let distances: Vec<u64> = found.iter().map(|id| id ^ 8).collect();
+    let mut sorted = distances.clone();
+    sorted.sort();
+    assert_eq!(distances, sorted);
+  }
+
+  #[test]
+  fn find_preferred_closest_prioritizes_reliable_nodes_over_raw_distance() {
+    let table: KBucketsTable<u64, (), 4> = KBucketsTable::new(0, Duration::from_secs(60));
+    for id in [4u64, 5, 6, 7] {
+      assert_eq!(table.update(id, ()), UpdateOutcome::Added);
+    }
+    table.report_unreachable(&5); // closest to 4 after 4 itself, but now unreliable
+
+    // 5 is the closest to 4 after 4 itself, but it's no longer `Connected`, so it's pushed
+    // behind 6 and 7 even though they're farther away.
+    let found: Vec<u64> = table.find_preferred_closest(&4, 10).collect();
+    assert_eq!(found, vec![4, 6, 7, 5]);
+
+    let truncated: Vec<u64> = table.find_preferred_closest(&4, 2).collect();
+    assert_eq!(truncated, vec![4, 6]);
+  }
+
+  #[test]
+  fn entries_needing_republish_resets_after_mark_republished() {
+    let table: KBucketsTable<u64, (), 4> = KBucketsTable::new(0, Duration::from_secs(60))
+      .with_republish_interval(Duration::from_millis(20));
+
+    assert_eq!(table.update(4, ()), UpdateOutcome::Added);
+    assert!(table.entries_needing_republish().is_empty()); // interval hasn't elapsed yet
+
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(table.entries_needing_republish(), vec![(4, ())]);
+
+    // Once the caller has re-announced it, it shouldn't be reported again until the interval
+    // elapses a second time.
+    table.mark_republished(&4);
+    assert!(table.entries_needing_republish().is_empty());
+
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(table.entries_needing_republish(), vec![(4, ())]);
+  }
+
+  #[test]
+  fn buckets_needing_refresh_excludes_a_recently_touched_bucket() {
+    let table: KBucketsTable<u64, (), 4> = KBucketsTable::new(0, Duration::from_secs(60))
+      .with_refresh_interval(Duration::from_millis(20));
+
+    std::thread::sleep(Duration::from_millis(30));
+    let stale: Vec<usize> = table.buckets_needing_refresh().collect();
+    assert!(!stale.is_empty()); // nothing has ever been touched
+
+    // 4's bucket relative to `my_id` 0 is bucket 2; touching it resets its `last_update`, so it
+    // should drop off the stale list while the untouched buckets remain on it.
+    let bucket_num = table.bucket_num(&4).unwrap();
+    assert!(stale.contains(&bucket_num));
+    assert_eq!(table.update(4, ()), UpdateOutcome::Added);
+
+    let still_stale: Vec<usize> = table.buckets_needing_refresh().collect();
+    assert!(!still_stale.contains(&bucket_num));
+    assert!(still_stale.len() < stale.len());
+
+    // The refresh target for that bucket is a distance whose leading-zero count places it
+    // back in the same bucket.
+    let target = table.random_refresh_target(bucket_num);
+    let expected_leading_zeros = (u64::num_bits() - 1 - bucket_num) as u32;
+    assert_eq!(u64::leading_zeros(target), expected_leading_zeros);
+  }
+}